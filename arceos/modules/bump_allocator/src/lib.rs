@@ -1,6 +1,128 @@
 #![no_std]
 
-use allocator::{BaseAllocator, ByteAllocator, PageAllocator};
+use allocator::{AllocError, BaseAllocator, ByteAllocator, PageAllocator};
+
+/// Maximum number of discontiguous memory regions `EarlyAllocator` can track.
+///
+/// Boot-time RAM descriptions (e.g. several `reg` ranges from a devicetree)
+/// rarely hand over more than a handful of disjoint ranges, so a small fixed
+/// capacity avoids needing a heap this early.
+const MAX_REGIONS: usize = 4;
+
+/// Number of `u64` words backing each region's page-reclaim bitmap, i.e. up
+/// to `PAGE_BITMAP_WORDS * 64` page slots can be tracked for reuse per
+/// region. Pages beyond this window (`page_capacity`) fall back to the old
+/// "never freed" behaviour; every bitmap write, and every slot accepted for
+/// reuse, is bounded by `page_capacity` so an oversized region can neither
+/// panic nor corrupt the byte/page accounting.
+const PAGE_BITMAP_WORDS: usize = 64;
+
+/// One double-ended bump range inside `EarlyAllocator`.
+///
+/// [ bytes-used | avail-area | pages-used ]
+/// |            | -->    <-- |            |
+/// start       b_pos        p_pos       end
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,                          // 内存区域起始位置
+    end: usize,                            // 内存区域结束位置
+    b_pos: usize,                          // 字节分配的位置指针
+    p_pos: usize,                          // 页分配的位置指针
+    page_bitmap: [u64; PAGE_BITMAP_WORDS], // 页分配位图，bit=1 表示该页槽已被占用
+    page_capacity: usize,                  // 位图实际覆盖的页槽数
+}
+
+impl Region {
+    const fn empty() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            b_pos: 0,
+            p_pos: 0,
+            page_bitmap: [0; PAGE_BITMAP_WORDS],
+            page_capacity: 0,
+        }
+    }
+
+    const fn new(start: usize, size: usize) -> Self {
+        let end = start + size;
+        Self {
+            start,
+            end,
+            b_pos: start,
+            p_pos: end,
+            page_bitmap: [0; PAGE_BITMAP_WORDS],
+            page_capacity: 0,
+        }
+    }
+
+    fn is_page_free(&self, idx: usize) -> bool {
+        self.page_bitmap[idx / 64] & (1 << (idx % 64)) == 0
+    }
+
+    fn set_page_used(&mut self, idx: usize, used: bool) {
+        let mask = 1 << (idx % 64);
+        if used {
+            self.page_bitmap[idx / 64] |= mask;
+        } else {
+            self.page_bitmap[idx / 64] &= !mask;
+        }
+    }
+
+    /// Marks `[idx, idx + num_pages)` as used/free. Slots at or beyond the
+    /// tracked `page_capacity` window are left untouched.
+    fn mark_pages(&mut self, idx: usize, num_pages: usize, used: bool) {
+        let end = (idx + num_pages).min(self.page_capacity);
+        for i in idx..end {
+            self.set_page_used(i, used);
+        }
+    }
+
+    /// Counts free (reclaimed) slots among the first `window` bits, where
+    /// `window` must already be `<= page_capacity`.
+    fn free_holes(&self, window: usize) -> usize {
+        (0..window).filter(|&i| self.is_page_free(i)).count()
+    }
+
+    /// Finds the index of the next free page slot at or after `start`,
+    /// skipping whole runs of used slots a word at a time via
+    /// `trailing_ones` (built on `trailing_zeros` of the complement).
+    fn next_free_page(&self, start: usize) -> Option<usize> {
+        let mut word_idx = start / 64;
+        let mut bit = start % 64;
+        while word_idx < self.page_bitmap.len() {
+            let word = self.page_bitmap[word_idx] >> bit;
+            let skip = word.trailing_ones() as usize;
+            let remaining = 64 - bit;
+            if skip < remaining {
+                return Some(word_idx * 64 + bit + skip);
+            }
+            word_idx += 1;
+            bit = 0;
+        }
+        None
+    }
+
+    /// Finds a free, contiguous run of `num_pages` slots within the first
+    /// `limit` slots (the already-allocated-then-freed window nearest
+    /// `end`), reusing previously freed pages instead of growing further.
+    /// Slots at or beyond `limit` have never been handed out by the grow
+    /// path and must not be treated as reusable.
+    fn find_free_run(&self, limit: usize, num_pages: usize) -> Option<usize> {
+        let mut idx = 0;
+        while idx + num_pages <= limit {
+            idx = self.next_free_page(idx)?;
+            if idx + num_pages > limit {
+                return None;
+            }
+            match (idx..idx + num_pages).find(|&i| !self.is_page_free(i)) {
+                None => return Some(idx),
+                Some(used_at) => idx = used_at + 1,
+            }
+        }
+        None
+    }
+}
 
 /// Early memory allocator
 /// Use it before formal bytes-allocator and pages-allocator can work!
@@ -14,39 +136,84 @@ use allocator::{BaseAllocator, ByteAllocator, PageAllocator};
 ///
 /// For bytes area, 'count' records number of allocations.
 /// When it goes down to ZERO, free bytes-used area.
-/// For pages area, it will never be freed!
+/// For pages area, freed runs are tracked in a per-region bitmap so they can
+/// be reused; see `dealloc_pages`.
 ///
+/// `add_memory` can hand over additional, disjoint ranges (e.g. more RAM
+/// discovered from firmware after boot); `alloc`/`alloc_pages` then try each
+/// region in turn, in the order it was added, for one with enough room.
 pub struct EarlyAllocator<const SIZE: usize> {
-    start: usize,      // 内存区域起始位置
-    end: usize,        // 内存区域结束位置
-    b_pos: usize,      // 字节分配的位置指针
-    p_pos: usize,      // 页分配的位置指针
-    count: usize,      // 记录字节分配的次数
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
+    count: usize,                              // 记录字节分配的次数
+    last_alloc: Option<(usize, usize, usize)>, // 最近一次字节分配：(区域下标, 起始地址, 大小)
 }
 
 impl<const SIZE: usize> EarlyAllocator<SIZE> {
     pub const fn new() -> Self {
         Self {
-            start: 0,
-            end: 0,
-            b_pos: 0,
-            p_pos: 0,
+            regions: [Region::empty(); MAX_REGIONS],
+            region_count: 0,
             count: 0,
+            last_alloc: None,
         }
     }
+
+    /// Builds a `Region` whose page bitmap covers as much of `size` as the
+    /// fixed-capacity bitmap allows.
+    fn make_region(start: usize, size: usize) -> Region {
+        let mut region = Region::new(start, size);
+        region.page_capacity = (size / SIZE).min(PAGE_BITMAP_WORDS * 64);
+        region
+    }
+
+    /// Reports the still-unallocated `[b_pos, p_pos)` window of every region
+    /// as `(start, length)` pairs, writing into `out` and returning how many
+    /// pairs were written (capped at `out.len()`). Regions whose window is
+    /// empty are skipped.
+    ///
+    /// This is how the early allocator hands its leftover memory off to the
+    /// permanent byte/page allocators once they come online, without losing
+    /// the free middle of each region.
+    pub fn remain_areas(&self, out: &mut [(usize, usize)]) -> usize {
+        let mut n = 0;
+        for region in self.regions[..self.region_count].iter() {
+            if n >= out.len() {
+                break;
+            }
+            if region.p_pos > region.b_pos {
+                out[n] = (region.b_pos, region.p_pos - region.b_pos);
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// Consumes the allocator, returning the same `(start, length)` pairs as
+    /// [`Self::remain_areas`] together with how many of them are valid.
+    pub fn finish(self) -> ([(usize, usize); MAX_REGIONS], usize) {
+        let mut out = [(0usize, 0usize); MAX_REGIONS];
+        let n = self.remain_areas(&mut out);
+        (out, n)
+    }
 }
 
 impl<const SIZE: usize> BaseAllocator for EarlyAllocator<SIZE> {
     fn init(&mut self, start: usize, size: usize) {
-        self.start = start;
-        self.end = start + size;
-        self.b_pos = start;
-        self.p_pos = self.end;
+        self.regions = [Region::empty(); MAX_REGIONS];
+        self.regions[0] = Self::make_region(start, size);
+        self.region_count = 1;
         self.count = 0;
+        self.last_alloc = None;
     }
 
     fn add_memory(&mut self, start: usize, size: usize) -> allocator::AllocResult {
-        todo!()
+        if self.region_count >= MAX_REGIONS {
+            return Err(AllocError::NoMemory);
+        }
+        self.regions[self.region_count] = Self::make_region(start, size);
+        self.region_count += 1;
+        Ok(())
     }
 }
 
@@ -58,41 +225,66 @@ impl<const SIZE: usize> ByteAllocator for EarlyAllocator<SIZE> {
         // 计算对齐后的分配位置
         let align = layout.align();
         let size = layout.size();
-        let aligned_pos = (self.b_pos + align - 1) & !(align - 1);
-        let new_b_pos = aligned_pos + size;
-        // 检查是否有足够的空间
-        if new_b_pos > self.p_pos {
-            return Err(allocator::AllocError::NoMemory);
+        // 依次尝试每个区域，使用第一个放得下的
+        for (index, region) in self.regions[..self.region_count].iter_mut().enumerate() {
+            let aligned_pos = (region.b_pos + align - 1) & !(align - 1);
+            let new_b_pos = aligned_pos + size;
+            // 检查是否有足够的空间
+            if new_b_pos <= region.p_pos {
+                // 更新分配位置和计数器
+                region.b_pos = new_b_pos;
+                self.count += 1;
+                // 记录本次分配，供 LIFO 释放时回退 b_pos
+                self.last_alloc = Some((index, aligned_pos, size));
+                // 返回分配的内存地址
+                return core::ptr::NonNull::new(aligned_pos as *mut u8)
+                    .ok_or(allocator::AllocError::NoMemory);
+            }
         }
-        // 更新分配位置和计数器
-        self.b_pos = new_b_pos;
-        self.count += 1;
-        // 返回分配的内存地址
-        core::ptr::NonNull::new(aligned_pos as *mut u8).ok_or(allocator::AllocError::NoMemory)
+        Err(allocator::AllocError::NoMemory)
     }
 
     fn dealloc(&mut self, pos: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
         if self.count > 0 {
             // 更新计数器
             self.count -= 1;
+            // 如果释放的正好是最近一次分配的那块，回退 b_pos 以便复用空间
+            if let Some((index, start, size)) = self.last_alloc {
+                if pos.as_ptr() as usize == start && layout.size() == size {
+                    self.regions[index].b_pos = start;
+                    self.last_alloc = None;
+                }
+            }
             // 所有分配的字节都被释放
             if self.count == 0 {
-                // 重置分配位置
-                self.b_pos = self.start;
+                // 重置每个区域的分配位置
+                for region in self.regions[..self.region_count].iter_mut() {
+                    region.b_pos = region.start;
+                }
+                self.last_alloc = None;
             }
         }
     }
 
     fn total_bytes(&self) -> usize {
-        self.end - self.start
+        self.regions[..self.region_count]
+            .iter()
+            .map(|r| r.end - r.start)
+            .sum()
     }
 
     fn used_bytes(&self) -> usize {
-        (self.b_pos - self.start) + (self.end - self.p_pos)
+        self.regions[..self.region_count]
+            .iter()
+            .map(|r| (r.b_pos - r.start) + (r.end - r.p_pos))
+            .sum()
     }
 
     fn available_bytes(&self) -> usize {
-        self.p_pos - self.b_pos
+        self.regions[..self.region_count]
+            .iter()
+            .map(|r| r.p_pos - r.b_pos)
+            .sum()
     }
 }
 
@@ -104,32 +296,164 @@ impl<const SIZE: usize> PageAllocator for EarlyAllocator<SIZE> {
         num_pages: usize,
         align_pow2: usize,
     ) -> allocator::AllocResult<usize> {
-        // 计算对齐后的分配位置
         let align = 1 << align_pow2;
         let size = num_pages * Self::PAGE_SIZE;
-        let aligned_pos = (self.p_pos - size) & !(align - 1);
-        // 检查是否有足够的空间
-        if aligned_pos < self.b_pos {
-            return Err(allocator::AllocError::NoMemory);
+        // 位图按页粒度对齐；更大的对齐要求直接走游标增长路径
+        if align <= Self::PAGE_SIZE {
+            for region in self.regions[..self.region_count].iter_mut() {
+                // 只在“已经分配过又被释放”的窗口里找空位，不能侵占
+                // p_pos 还没增长到过的区域，否则会和字节区重叠；同时不能
+                // 超出位图实际能覆盖的 page_capacity，否则会越界
+                let freed_window =
+                    ((region.end - region.p_pos) / Self::PAGE_SIZE).min(region.page_capacity);
+                if let Some(idx) = region.find_free_run(freed_window, num_pages) {
+                    let addr = region.end - (idx + num_pages) * Self::PAGE_SIZE;
+                    if addr >= region.b_pos {
+                        region.mark_pages(idx, num_pages, true);
+                        return Ok(addr);
+                    }
+                }
+            }
         }
-        // 更新分配位置
-        self.p_pos = aligned_pos;
-        Ok(aligned_pos)
+        // 回收区没有合适的空闲块，回退到原先从 p_pos 向下增长的分配方式
+        for region in self.regions[..self.region_count].iter_mut() {
+            let aligned_pos = (region.p_pos - size) & !(align - 1);
+            if aligned_pos >= region.b_pos {
+                region.p_pos = aligned_pos;
+                let idx = (region.end - aligned_pos) / Self::PAGE_SIZE - num_pages;
+                region.mark_pages(idx, num_pages, true);
+                return Ok(aligned_pos);
+            }
+        }
+        Err(allocator::AllocError::NoMemory)
     }
 
     fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
-        todo!()
+        for region in self.regions[..self.region_count].iter_mut() {
+            if pos < region.start || pos + num_pages * Self::PAGE_SIZE > region.end {
+                continue;
+            }
+            let idx = (region.end - pos) / Self::PAGE_SIZE - num_pages;
+            region.mark_pages(idx, num_pages, false);
+            // 如果释放的这块正好是页区当前的下边界（p_pos），就把 p_pos
+            // 上移回收这块空间，并继续向 end 方向吞并后续已经空闲的页。
+            // 只有落在 page_capacity 覆盖范围内的槽位才查位图，位图之外的
+            // 槽位从未被标记过，不能当成空闲处理
+            if pos == region.p_pos {
+                let mut new_p_pos = pos + num_pages * Self::PAGE_SIZE;
+                if idx < region.page_capacity {
+                    let mut idx_cursor = idx;
+                    while idx_cursor > 0 && region.is_page_free(idx_cursor - 1) {
+                        idx_cursor -= 1;
+                        new_p_pos += Self::PAGE_SIZE;
+                    }
+                }
+                region.p_pos = new_p_pos;
+            }
+            return;
+        }
     }
 
     fn total_pages(&self) -> usize {
-        (self.end - self.start) / Self::PAGE_SIZE
+        self.regions[..self.region_count]
+            .iter()
+            .map(|r| (r.end - r.start) / Self::PAGE_SIZE)
+            .sum()
     }
 
     fn used_pages(&self) -> usize {
-        (self.end - self.p_pos) / Self::PAGE_SIZE
+        self.total_pages() - self.available_pages()
     }
 
     fn available_pages(&self) -> usize {
-        (self.p_pos - self.b_pos) / Self::PAGE_SIZE
+        self.regions[..self.region_count]
+            .iter()
+            .map(|r| {
+                // 空闲页 = b_pos/p_pos 之间的空闲区 + 位图窗口内已回收的空洞。
+                // 位图窗口之外的页槽从未被标记过，无法判断是否空闲，不计入。
+                let free_middle = (r.p_pos - r.b_pos) / Self::PAGE_SIZE;
+                let window = ((r.end - r.p_pos) / Self::PAGE_SIZE).min(r.page_capacity);
+                free_middle + r.free_holes(window)
+            })
+            .sum()
     }
-}
\ No newline at end of file
+}
+
+/// Devicetree-driven self-configuration, for platforms (RISC-V/ARM virt)
+/// where available RAM is only known from the FDT blob passed in at boot.
+/// Kept behind a feature so `no_std` users without a devicetree aren't
+/// forced to pull the `fdt` crate in.
+#[cfg(feature = "fdt")]
+impl<const SIZE: usize> EarlyAllocator<SIZE> {
+    /// Walks the `/memory` nodes' `reg` ranges of the devicetree blob at
+    /// `fdt_ptr`, feeding each range into `init` (the first one) or
+    /// `add_memory` (the rest). Where a range overlaps
+    /// `[kernel_start, kernel_end)` only the kernel image itself is carved
+    /// out; the usable sub-ranges before and/or after it are kept, since on
+    /// single-bank platforms the kernel typically lives inside the one
+    /// `/memory` range and dropping the whole bank would leave nothing.
+    ///
+    /// # Safety
+    /// `fdt_ptr` must point to a valid devicetree blob that stays mapped and
+    /// unmodified for the duration of this call.
+    pub unsafe fn init_from_fdt(
+        &mut self,
+        fdt_ptr: *const u8,
+        kernel_start: usize,
+        kernel_end: usize,
+    ) -> Result<(), &'static str> {
+        let fdt = fdt::Fdt::from_ptr(fdt_ptr).map_err(|_| "invalid devicetree blob")?;
+        let mut added = false;
+        let mut truncated = false;
+        'outer: for region in fdt.memory().regions() {
+            let start = region.starting_address as usize;
+            let Some(size) = region.size else {
+                continue;
+            };
+            let end = start + size;
+
+            // 把与内核镜像重叠的部分挖掉，保留镜像前后仍然可用的子区间
+            let overlap_start = kernel_start.max(start);
+            let overlap_end = kernel_end.min(end);
+            let mut sub_ranges = [(0usize, 0usize); 2];
+            let mut n = 0;
+            if overlap_start < overlap_end {
+                if start < overlap_start {
+                    sub_ranges[n] = (start, overlap_start - start);
+                    n += 1;
+                }
+                if overlap_end < end {
+                    sub_ranges[n] = (overlap_end, end - overlap_end);
+                    n += 1;
+                }
+            } else {
+                sub_ranges[n] = (start, size);
+                n += 1;
+            }
+
+            for &(sub_start, sub_size) in &sub_ranges[..n] {
+                if sub_size == 0 {
+                    continue;
+                }
+                if !added {
+                    self.init(sub_start, sub_size);
+                    added = true;
+                } else if self.add_memory(sub_start, sub_size).is_err() {
+                    // MAX_REGIONS 用完了，剩下的 sub-range 和后续的 /memory
+                    // 节点都装不下，如实报告而不是悄悄丢弃这些内存
+                    truncated = true;
+                    break 'outer;
+                } else {
+                    added = true;
+                }
+            }
+        }
+        if truncated {
+            Err("more usable memory ranges than MAX_REGIONS could track; some were dropped")
+        } else if added {
+            Ok(())
+        } else {
+            Err("no usable /memory regions found in devicetree")
+        }
+    }
+}